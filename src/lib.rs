@@ -1,13 +1,22 @@
 #[macro_use]
 extern crate vst;
 
+mod params;
+mod wav;
+
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
 use ahash::AHashMap;
 use keyframe::{ease_with_scaled_time, functions::Linear};
+use params::{SquarezoidParams, PARAMETER_COUNT};
+use rand::Rng;
 use vst::{
     api::{Events, Supported},
     buffer::AudioBuffer,
     event::Event,
-    plugin::{CanDo, Category, Info, Plugin},
+    plugin::{CanDo, Category, Info, Plugin, PluginParameters},
 };
 
 /// Get the actual frequency represented by a u7 pitch and a u14 bend.
@@ -32,12 +41,214 @@ struct Squarezoid {
     bend: u16,
 
     sample_rate: f64,
+
+    /// Host-automatable parameters (gain, duty offset, glide, ADSR, ...).
+    params: Arc<SquarezoidParams>,
+
+    /// Whether the sustain pedal (CC 64) is currently held down.
+    sustain_pedal: bool,
+    /// Target channel volume from CC 7, in `[0, 1]`.
+    channel_volume: f64,
+    /// Channel volume actually applied, smoothed toward `channel_volume`.
+    channel_volume_smoothed: f64,
+    /// Target mod wheel position from CC 1, in `[0, 1]`.
+    mod_wheel: f64,
+    /// Mod wheel amount actually applied, smoothed toward `mod_wheel`.
+    mod_wheel_smoothed: f64,
+
+    /// Round-robin counter used to spread successive notes across the
+    /// stereo field.
+    pan_counter: u32,
+
+    /// Whether the record parameter was on as of the last `process` call.
+    recording: bool,
+    /// Captured stereo output, waiting to be flushed to a `.wav` file.
+    record_buffer: Vec<(f32, f32)>,
+    /// Hands finished captures to the background writer thread so the
+    /// actual file I/O never happens on the audio thread.
+    record_tx: Sender<(u32, Vec<(f32, f32)>)>,
 }
 
+/// Where a render capture gets written when recording is toggled off.
+const RECORDING_PATH: &str = "squarezoid_recording.wav";
+
+/// How many round-robin slots notes are panned across.
+const PAN_SLOTS: u32 = 5;
+
+/// Time constant, in seconds, for smoothing CC-driven gain changes so
+/// they don't zipper.
+const CC_SMOOTH_TIME: f64 = 0.05;
+
 struct Note {
     velocity: u8,
-    /// How long this note has been held for
-    duration: f64,
+    /// Normalized oscillator phase in `[0, 1)`.
+    phase: f64,
+    /// Which leg of the ADSR envelope this note is currently on.
+    stage: EnvelopeStage,
+    /// Current envelope amplitude, `[0, 1]`.
+    amplitude: f64,
+    /// Frequency actually being played, which glides toward the pitch's
+    /// true frequency at a rate set by the glide parameter.
+    current_freq: f64,
+    /// Set when a note-off arrives while the sustain pedal is held; the
+    /// note is released once the pedal lifts instead of immediately.
+    sustained: bool,
+    /// This note's position in the stereo field, `[0, 1]` from left to
+    /// right, before the pan spread parameter narrows it toward center.
+    pan: f64,
+    /// Running integral used to derive the triangle wave from the square.
+    triangle_integrator: f64,
+}
+
+/// A leg of an ADSR amplitude envelope.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Advance a note's envelope by one sample, given the ADSR times (in
+/// seconds, except `sustain` which is a level in `[0, 1]`).
+fn advance_envelope(note: &mut Note, per_sample: f64, attack: f64, decay: f64, sustain: f64, release: f64) {
+    match note.stage {
+        EnvelopeStage::Attack => {
+            note.amplitude += per_sample / attack.max(1e-6);
+            if note.amplitude >= 1.0 {
+                note.amplitude = 1.0;
+                note.stage = EnvelopeStage::Decay;
+            }
+        }
+        EnvelopeStage::Decay => {
+            note.amplitude -= per_sample * (1.0 - sustain) / decay.max(1e-6);
+            if note.amplitude <= sustain {
+                note.amplitude = sustain;
+                note.stage = EnvelopeStage::Sustain;
+            }
+        }
+        EnvelopeStage::Sustain => {
+            note.amplitude = sustain;
+        }
+        EnvelopeStage::Release => {
+            // exponential falloff, not linear, so the tail sounds natural
+            // and long releases don't pop at the very end
+            let falloff = (1e-4_f64).powf(per_sample / release.max(1e-6));
+            note.amplitude *= falloff;
+        }
+    }
+}
+
+/// Envelope amplitude below which a releasing note is considered silent
+/// and can be dropped from the note map.
+const RELEASE_CUTOFF: f64 = 1e-4;
+
+/// PolyBLEP (polynomial band-limited step) correction, used to smooth a
+/// discontinuity that falls at phase `t` within a sample period of
+/// length `dt` (i.e. `dt = freq / sample_rate`).
+///
+/// This is added at a rising edge and subtracted (with a phase-shifted
+/// `t`) at a falling edge to kill the aliasing a naive hard step produces.
+fn polyblep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let x = t / dt;
+        2.0 * x - x * x - 1.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// A band-limited square wave with the given duty cycle, at phase `t`
+/// within a sample period of length `dt`.
+fn square_wave(t: f64, dt: f64, duty: f64) -> f64 {
+    let naive = if t < duty { 1.0 } else { -1.0 };
+    naive + polyblep(t, dt) - polyblep((t + 1.0 - duty).fract(), dt)
+}
+
+/// Which shape an oscillator's phase is turned into.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Waveform {
+    Square,
+    Saw,
+    Triangle,
+    Sine,
+}
+
+impl Waveform {
+    /// Map the waveform parameter's `[0, 1]` knob onto one of the four
+    /// waveforms, split into even quarters.
+    pub(crate) fn from_param(value: f64) -> Self {
+        match (value.clamp(0.0, 1.0) * 4.0) as u32 {
+            0 => Waveform::Square,
+            1 => Waveform::Saw,
+            2 => Waveform::Triangle,
+            _ => Waveform::Sine,
+        }
+    }
+
+    /// Display name shown in the host's parameter list.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Waveform::Square => "Square",
+            Waveform::Saw => "Saw",
+            Waveform::Triangle => "Triangle",
+            Waveform::Sine => "Sine",
+        }
+    }
+}
+
+/// Leak factor for the triangle wave's integrator, so DC offset from the
+/// integration doesn't build up over a long note.
+const TRIANGLE_LEAK: f64 = 0.999;
+
+/// Generate one sample from a note's oscillator, advancing any state
+/// (like the triangle wave's integrator) that isn't just the phase.
+fn oscillator(waveform: Waveform, note: &mut Note, dt: f64, duty: f64) -> f64 {
+    let t = note.phase;
+    match waveform {
+        Waveform::Square => square_wave(t, dt, duty),
+        Waveform::Saw => 2.0 * t - 1.0 - polyblep(t, dt),
+        Waveform::Sine => (std::f64::consts::TAU * t).sin(),
+        Waveform::Triangle => {
+            // integrating a band-limited square yields a band-limited triangle
+            let square = square_wave(t, dt, duty);
+            note.triangle_integrator = note.triangle_integrator * TRIANGLE_LEAK + square * dt * 4.0;
+            note.triangle_integrator
+        }
+    }
+}
+
+impl Squarezoid {
+    /// Hand off whatever's in `record_buffer` to the writer thread and
+    /// empty it. This must stay allocation-cheap (just a channel send) so
+    /// it's safe to call from `process`; the blocking file I/O happens on
+    /// `record_tx`'s receiving thread instead.
+    fn flush_recording(&mut self) {
+        if self.record_buffer.is_empty() {
+            return;
+        }
+        let buffer = std::mem::take(&mut self.record_buffer);
+        if self.record_tx.send((self.sample_rate as u32, buffer)).is_err() {
+            eprintln!("squarezoid: recording writer thread is gone, dropping capture");
+        }
+    }
+}
+
+/// Spawn the background thread that writes captures handed to it by
+/// `Squarezoid::flush_recording`, so disk I/O never blocks `process`.
+fn spawn_recording_writer() -> Sender<(u32, Vec<(f32, f32)>)> {
+    let (tx, rx) = mpsc::channel::<(u32, Vec<(f32, f32)>)>();
+    thread::spawn(move || {
+        for (sample_rate, buffer) in rx {
+            if let Err(e) = wav::write_wav(RECORDING_PATH, sample_rate, &buffer) {
+                eprintln!("squarezoid: failed to write {}: {}", RECORDING_PATH, e);
+            }
+        }
+    });
+    tx
 }
 
 impl Plugin for Squarezoid {
@@ -49,12 +260,16 @@ impl Plugin for Squarezoid {
             category: Category::Synth,
             inputs: 0,
             outputs: 2,
-            parameters: 0,
+            parameters: PARAMETER_COUNT,
             initial_delay: 0,
             ..Default::default()
         }
     }
 
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params)
+    }
+
     fn process_events(&mut self, events: &Events) {
         for evt in events.events() {
             if let Event::Midi(evt) = evt {
@@ -63,15 +278,52 @@ impl Plugin for Squarezoid {
                 match data[0] {
                     // note off event
                     128..=143 => {
-                        self.notes.remove(&data[1]);
+                        // move into the release tail instead of cutting the
+                        // note off instantly, to avoid a click -- unless the
+                        // sustain pedal is down, in which case hold it until
+                        // the pedal lifts
+                        if let Some(note) = self.notes.get_mut(&data[1]) {
+                            if self.sustain_pedal {
+                                note.sustained = true;
+                            } else {
+                                note.stage = EnvelopeStage::Release;
+                            }
+                        }
                     }
                     // note on
                     144..=159 => {
+                        let pan = (self.pan_counter % PAN_SLOTS) as f64 / (PAN_SLOTS - 1) as f64;
+                        self.pan_counter = self.pan_counter.wrapping_add(1);
+                        let velocity = if self.params.humanize.get() >= 0.5 {
+                            // a host can write NaN straight through `set_parameter`
+                            // (e.g. a corrupted preset); guard against it before it
+                            // reaches `clamp`, which panics on a NaN bound
+                            let min_vel = self.params.min_vel.get();
+                            let min_vel = if min_vel.is_nan() { 0.0 } else { min_vel.clamp(0.0, 127.0) };
+                            let max_vel = self.params.max_vel.get();
+                            let max_vel = if max_vel.is_nan() { 127.0 } else { max_vel.clamp(min_vel, 127.0) };
+                            rand::thread_rng().gen_range(min_vel..=max_vel) as u8
+                        } else {
+                            data[2]
+                        };
+                        // if this pitch is retriggered while its previous
+                        // voice is still releasing, start the new attack
+                        // from that voice's current amplitude instead of 0,
+                        // so the old release doesn't vanish in a single
+                        // sample and click
+                        let starting_amplitude =
+                            self.notes.get(&data[1]).map(|note| note.amplitude).unwrap_or(0.0);
                         self.notes.insert(
                             data[1],
                             Note {
-                                duration: 0.0,
-                                velocity: data[2],
+                                phase: 0.0,
+                                velocity,
+                                stage: EnvelopeStage::Attack,
+                                amplitude: starting_amplitude,
+                                current_freq: midi_pitch_to_freq(data[1], self.bend),
+                                sustained: false,
+                                pan,
+                                triangle_integrator: 0.0,
                             },
                         );
                     }
@@ -82,6 +334,42 @@ impl Plugin for Squarezoid {
                             note.velocity = data[2]
                         }
                     }
+                    // control change
+                    176..=191 => match data[1] {
+                        // mod wheel
+                        1 => self.mod_wheel = data[2] as f64 / 127.0,
+                        // channel volume
+                        7 => self.channel_volume = data[2] as f64 / 127.0,
+                        // sustain pedal
+                        64 => {
+                            self.sustain_pedal = data[2] >= 64;
+                            if !self.sustain_pedal {
+                                // pedal lifted: release any notes that were
+                                // only being held because of it
+                                for note in self.notes.values_mut() {
+                                    if note.sustained {
+                                        note.sustained = false;
+                                        note.stage = EnvelopeStage::Release;
+                                    }
+                                }
+                            }
+                        }
+                        // all notes off: per spec this is equivalent to a
+                        // note-off on every active note, so it goes through
+                        // the same release tail (and sustain pedal) rather
+                        // than cutting everything off instantly
+                        123 => {
+                            let sustain_pedal = self.sustain_pedal;
+                            for note in self.notes.values_mut() {
+                                if sustain_pedal {
+                                    note.sustained = true;
+                                } else {
+                                    note.stage = EnvelopeStage::Release;
+                                }
+                            }
+                        }
+                        _ => {}
+                    },
                     // pitch wheel
                     224..=239 => {
                         // the MSB are shifted over 7 because data[1] is a u7.
@@ -105,31 +393,77 @@ impl Plugin for Squarezoid {
 
         let per_sample = self.sample_rate.recip();
         let output_count = outputs.len();
+        let gain = self.params.gain.get() as f64;
+        let duty_offset = self.params.duty_offset.get() as f64 - 0.5;
+        let glide = self.params.glide.get() as f64;
+        let attack = self.params.attack.get() as f64;
+        let decay = self.params.decay.get() as f64;
+        let sustain = self.params.sustain.get() as f64;
+        // a host can write NaN straight through `set_parameter`; without this
+        // guard it propagates into `note.amplitude` forever and the note can
+        // never be evicted in the `retain` below (NaN comparisons are false)
+        let sustain = if sustain.is_nan() { 0.0 } else { sustain.clamp(0.0, 1.0) };
+        let release = self.params.release.get() as f64;
+        let pan_spread = self.params.pan_spread.get() as f64;
+        let waveform = Waveform::from_param(self.params.waveform.get() as f64);
+        let cc_smooth_coeff = 1.0 - (-per_sample / CC_SMOOTH_TIME).exp();
+
+        let record_on = self.params.record.get() >= 0.5;
+        if record_on && !self.recording {
+            self.record_buffer.clear();
+        } else if !record_on && self.recording {
+            self.flush_recording();
+        }
+        self.recording = record_on;
 
         for sample_idx in 0..sample_count {
             let bend = self.bend;
-            let sample: f64 = self
-                .notes
-                .iter_mut()
-                .map(|(&pitch, note)| {
-                    let duty = note.velocity as f64 / 127.0;
-                    let freq = midi_pitch_to_freq(pitch, bend);
-                    let sample_time = note.duration * freq;
-
-                    // how far are we along in this duty cycle?
-                    let duty_progress = sample_time.fract();
-                    let out = if duty_progress < duty { 0.0 } else { 0.01 };
-
-                    note.duration += per_sample;
-                    out
-                })
-                .sum();
-            // go thru left and right channels
+            self.channel_volume_smoothed +=
+                (self.channel_volume - self.channel_volume_smoothed) * cc_smooth_coeff;
+            self.mod_wheel_smoothed += (self.mod_wheel - self.mod_wheel_smoothed) * cc_smooth_coeff;
+            // mod wheel adds extra gain above unity; at rest it's a no-op
+            let cc_gain = self.channel_volume_smoothed * (1.0 + self.mod_wheel_smoothed);
+            let (mut left, mut right) = (0.0_f64, 0.0_f64);
+            for (&pitch, note) in self.notes.iter_mut() {
+                let duty = (note.velocity as f64 / 127.0 + duty_offset).clamp(0.01, 0.99);
+                let target_freq = midi_pitch_to_freq(pitch, bend);
+                if glide > 1e-6 {
+                    let coeff = 1.0 - (-per_sample / glide).exp();
+                    note.current_freq += (target_freq - note.current_freq) * coeff;
+                } else {
+                    note.current_freq = target_freq;
+                }
+                let dt = note.current_freq * per_sample;
+
+                let out = oscillator(waveform, note, dt, duty);
+                note.phase = (note.phase + dt).fract();
+                advance_envelope(note, per_sample, attack, decay, sustain, release);
+                let out = out * gain * cc_gain * note.amplitude;
+
+                // equal-power pan, narrowed toward center by `pan_spread`
+                let pan = 0.5 + (note.pan - 0.5) * pan_spread;
+                let theta = pan * std::f64::consts::FRAC_PI_2;
+                left += out * theta.cos();
+                right += out * theta.sin();
+            }
+
+            if self.recording {
+                self.record_buffer.push((left as f32, right as f32));
+            }
+            // go thru left and right channels; any beyond stereo just get the mono sum
             for buf_idx in 0..output_count {
                 let buf = outputs.get_mut(buf_idx);
-                buf[sample_idx] = sample as f32;
+                buf[sample_idx] = match buf_idx {
+                    0 => left as f32,
+                    1 => right as f32,
+                    _ => (left + right) as f32,
+                };
             }
         }
+
+        // drop notes once their release tail has decayed to silence
+        self.notes
+            .retain(|_, note| !(note.stage == EnvelopeStage::Release && note.amplitude < RELEASE_CUTOFF));
     }
 
     fn can_do(&self, can_do: CanDo) -> Supported {
@@ -145,6 +479,16 @@ impl Default for Squarezoid {
             notes: AHashMap::with_capacity(8),
             sample_rate: 44100.0,
             bend: 8192,
+            params: Arc::new(SquarezoidParams::default()),
+            sustain_pedal: false,
+            channel_volume: 1.0,
+            channel_volume_smoothed: 1.0,
+            mod_wheel: 0.0,
+            mod_wheel_smoothed: 0.0,
+            pan_counter: 0,
+            recording: false,
+            record_buffer: Vec::new(),
+            record_tx: spawn_recording_writer(),
         }
     }
 }
@@ -0,0 +1,162 @@
+use vst::plugin::PluginParameters;
+use vst::util::AtomicFloat;
+
+use crate::Waveform;
+
+/// Index of each automatable parameter, in the order the host sees them.
+mod index {
+    pub const GAIN: i32 = 0;
+    pub const DUTY_OFFSET: i32 = 1;
+    pub const GLIDE: i32 = 2;
+    pub const WAVEFORM: i32 = 3;
+    pub const ATTACK: i32 = 4;
+    pub const DECAY: i32 = 5;
+    pub const SUSTAIN: i32 = 6;
+    pub const RELEASE: i32 = 7;
+    pub const PAN_SPREAD: i32 = 8;
+    pub const RECORD: i32 = 9;
+    pub const HUMANIZE: i32 = 10;
+    pub const MIN_VEL: i32 = 11;
+    pub const MAX_VEL: i32 = 12;
+}
+
+/// How many automatable parameters Squarezoid reports to the host.
+pub const PARAMETER_COUNT: i32 = 13;
+
+/// All of Squarezoid's automatable parameters, stored as atomics so the
+/// host's GUI/automation thread can write to them while the audio thread
+/// reads them in `process`.
+pub struct SquarezoidParams {
+    /// Master output gain.
+    pub gain: AtomicFloat,
+    /// Offset added to the velocity-driven duty cycle, stored as a knob
+    /// in `[0, 1]` where `0.5` is "no offset".
+    pub duty_offset: AtomicFloat,
+    /// Glide time constant, in seconds, with which each note's frequency
+    /// follows pitch-bend changes (`0` is instant). A new note starts at its
+    /// own pitch; it does not glide from the previous note.
+    pub glide: AtomicFloat,
+    /// Which waveform to generate. `[0, 1]`, split into even quarters for
+    /// square, saw, triangle and sine (see `Waveform::from_param`).
+    pub waveform: AtomicFloat,
+    /// Envelope attack time, in seconds.
+    pub attack: AtomicFloat,
+    /// Envelope decay time, in seconds.
+    pub decay: AtomicFloat,
+    /// Envelope sustain level, in `[0, 1]`.
+    pub sustain: AtomicFloat,
+    /// Envelope release time, in seconds.
+    pub release: AtomicFloat,
+    /// How wide notes are spread across the stereo field, `[0, 1]`.
+    pub pan_spread: AtomicFloat,
+    /// Toggle (treated as on at `>= 0.5`): while on, output is captured
+    /// to a `.wav` file; flipping it back off flushes the capture.
+    pub record: AtomicFloat,
+    /// Toggle (treated as on at `>= 0.5`): while on, incoming note
+    /// velocities are randomized within `[min_vel, max_vel)` instead of
+    /// being used as-is.
+    pub humanize: AtomicFloat,
+    /// Lower bound for randomized velocity, in raw MIDI units `[0, 127]`.
+    pub min_vel: AtomicFloat,
+    /// Upper bound for randomized velocity, in raw MIDI units `[0, 127]`.
+    pub max_vel: AtomicFloat,
+}
+
+impl Default for SquarezoidParams {
+    fn default() -> Self {
+        Self {
+            gain: AtomicFloat::new(0.01),
+            duty_offset: AtomicFloat::new(0.5),
+            glide: AtomicFloat::new(0.0),
+            waveform: AtomicFloat::new(0.0),
+            attack: AtomicFloat::new(0.01),
+            decay: AtomicFloat::new(0.1),
+            sustain: AtomicFloat::new(0.8),
+            release: AtomicFloat::new(0.2),
+            pan_spread: AtomicFloat::new(0.5),
+            record: AtomicFloat::new(0.0),
+            humanize: AtomicFloat::new(0.0),
+            min_vel: AtomicFloat::new(64.0),
+            max_vel: AtomicFloat::new(127.0),
+        }
+    }
+}
+
+impl PluginParameters for SquarezoidParams {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            index::GAIN => self.gain.get(),
+            index::DUTY_OFFSET => self.duty_offset.get(),
+            index::GLIDE => self.glide.get(),
+            index::WAVEFORM => self.waveform.get(),
+            index::ATTACK => self.attack.get(),
+            index::DECAY => self.decay.get(),
+            index::SUSTAIN => self.sustain.get(),
+            index::RELEASE => self.release.get(),
+            index::PAN_SPREAD => self.pan_spread.get(),
+            index::RECORD => self.record.get(),
+            index::HUMANIZE => self.humanize.get(),
+            index::MIN_VEL => self.min_vel.get(),
+            index::MAX_VEL => self.max_vel.get(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, value: f32) {
+        match index {
+            index::GAIN => self.gain.set(value),
+            index::DUTY_OFFSET => self.duty_offset.set(value),
+            index::GLIDE => self.glide.set(value),
+            index::WAVEFORM => self.waveform.set(value),
+            index::ATTACK => self.attack.set(value),
+            index::DECAY => self.decay.set(value),
+            index::SUSTAIN => self.sustain.set(value),
+            index::RELEASE => self.release.set(value),
+            index::PAN_SPREAD => self.pan_spread.set(value),
+            index::RECORD => self.record.set(value),
+            index::HUMANIZE => self.humanize.set(value),
+            index::MIN_VEL => self.min_vel.set(value),
+            index::MAX_VEL => self.max_vel.set(value),
+            _ => (),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            index::GAIN => "Gain",
+            index::DUTY_OFFSET => "Duty",
+            index::GLIDE => "Glide",
+            index::WAVEFORM => "Waveform",
+            index::ATTACK => "Attack",
+            index::DECAY => "Decay",
+            index::SUSTAIN => "Sustain",
+            index::RELEASE => "Release",
+            index::PAN_SPREAD => "Pan Spread",
+            index::RECORD => "Record",
+            index::HUMANIZE => "Humanize",
+            index::MIN_VEL => "Min Velocity",
+            index::MAX_VEL => "Max Velocity",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            index::GAIN => format!("{:.3}", self.gain.get()),
+            index::DUTY_OFFSET => format!("{:.0}%", (self.duty_offset.get() - 0.5) * 100.0),
+            index::GLIDE => format!("{:.0} ms", self.glide.get() * 1000.0),
+            index::WAVEFORM => Waveform::from_param(self.waveform.get() as f64).name().to_string(),
+            index::ATTACK => format!("{:.0} ms", self.attack.get() * 1000.0),
+            index::DECAY => format!("{:.0} ms", self.decay.get() * 1000.0),
+            index::SUSTAIN => format!("{:.0}%", self.sustain.get() * 100.0),
+            index::RELEASE => format!("{:.0} ms", self.release.get() * 1000.0),
+            index::PAN_SPREAD => format!("{:.0}%", self.pan_spread.get() * 100.0),
+            index::RECORD => if self.record.get() >= 0.5 { "On" } else { "Off" }.to_string(),
+            index::HUMANIZE => if self.humanize.get() >= 0.5 { "On" } else { "Off" }.to_string(),
+            index::MIN_VEL => format!("{:.0}", self.min_vel.get()),
+            index::MAX_VEL => format!("{:.0}", self.max_vel.get()),
+            _ => "".to_string(),
+        }
+    }
+}